@@ -2,13 +2,15 @@ mod musync;
 
 use std::{io, path::PathBuf, time::Instant};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use colored::Colorize;
 use musync::musync;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(short, help = "Directory to sync from")]
     src: PathBuf,
     #[arg(short, help = "Directory to sync to")]
@@ -21,6 +23,12 @@ struct Cli {
     bitrate: usize,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Re-check dst against the state file and re-derive anything missing or damaged
+    Verify,
+}
+
 fn main() -> io::Result<()> {
     let instant = Instant::now();
     let cli = Cli::parse();
@@ -35,7 +43,10 @@ fn main() -> io::Result<()> {
 |___|___| \__,_| \___||____/ |__|__|\____|"#
             .cyan()
     );
-    musync(cli.src, cli.dst, cli.jobs, cli.bitrate)?;
+    match cli.command {
+        None => musync(cli.src, cli.dst, cli.jobs, cli.bitrate)?,
+        Some(Command::Verify) => musync::verify(cli.src, cli.dst, cli.jobs, cli.bitrate)?,
+    }
     eprintln!(
         "Finished in {}",
         instant.elapsed().as_secs_f32().to_string().green().bold()
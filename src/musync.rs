@@ -1,12 +1,15 @@
 use std::{
     fs::{self, File},
-    io::{self, BufRead, Read, Write},
+    io::{self, BufRead, Read, Seek, Write},
     path::{Path, PathBuf},
     process::{Child, Command},
+    sync::mpsc,
+    time::UNIX_EPOCH,
 };
 
 use colored::Colorize;
 use fxhash::{FxHashMap, FxHashSet};
+use rayon::prelude::*;
 use sha2::{Digest, Sha512};
 use smol_str::SmolStr;
 use walkdir::WalkDir;
@@ -14,7 +17,7 @@ use walkdir::WalkDir;
 const EXTENSIONS_TO_CONVERT: [&str; 7] =
     ["aiff", "flac", "flac", "ogg", "mod", "xm", "m4a"];
 const STATE_FILE: &str = ".musync";
-const MAX_JOBS: usize = 16;
+const PARTIAL_HASH_SIZE: usize = 1048576;
 
 /// Return an iterator to the Reader of the lines of the file.
 fn read_lines<P>(path: P) -> io::Result<io::Lines<io::BufReader<File>>>
@@ -23,34 +26,53 @@ where P: AsRef<Path> {
     Ok(io::BufReader::new(file).lines())
 }
 
-/// Read a 2-column table into a fxhash map. n is the length of the first column.
+/// A synced file's record: its source identity (size + modification time, to
+/// skip re-hashing unchanged files) and where it lives on both sides. The
+/// partial hash is kept alongside the final one so a cache hit can still be
+/// checked for a collision against newly-discovered files without re-reading
+/// its (unchanged) source from disk.
+struct StateEntry {
+    size: u64,
+    mtime_ns: u128,
+    partial_hash: SmolStr,
+    src_path: SmolStr,
+    dst_path: SmolStr,
+}
+
+/// Read the `.musync` state file into a fxhash map keyed by content hash:
+/// the full-file hash where a partial-hash collision forced one, otherwise
+/// the partial hash. Each line is `hash\tsize\tmtime_ns\tpartial_hash\tsrc_path\tdst_path`.
 /// If the file does not exist, an empty map is returned.
-fn read_table<P>(path: P, n: usize) -> io::Result<FxHashMap<SmolStr, SmolStr>>
+fn read_state<P>(path: P) -> io::Result<FxHashMap<SmolStr, StateEntry>>
 where P: AsRef<Path> {
-    let mut table: FxHashMap<SmolStr, SmolStr> = FxHashMap::default();
+    let mut table: FxHashMap<SmolStr, StateEntry> = FxHashMap::default();
     if let Ok(lines) = read_lines(path) {
         for line in lines {
             let line = line?;
-            if line.len() < n {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "line too short",
-                ));
-            }
-            let hash = line[0..n].into();
-            let path = line[n..].into();
-            table.insert(hash, path);
+            let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed state line");
+            let mut columns = line.splitn(6, '\t');
+            let hash = columns.next().ok_or_else(malformed)?.into();
+            let size = columns.next().ok_or_else(malformed)?.parse().map_err(|_| malformed())?;
+            let mtime_ns = columns.next().ok_or_else(malformed)?.parse().map_err(|_| malformed())?;
+            let partial_hash = columns.next().ok_or_else(malformed)?.into();
+            let src_path = columns.next().ok_or_else(malformed)?.into();
+            let dst_path = columns.next().ok_or_else(malformed)?.into();
+            table.insert(hash, StateEntry { size, mtime_ns, partial_hash, src_path, dst_path });
         }
     }
     Ok(table)
 }
 
-/// Write a 2-column table into a file. Does not include any separator.
-fn write_table<P>(path: P, table: &FxHashMap<SmolStr, SmolStr>) -> io::Result<()>
+/// Write the `.musync` state file. See `read_state` for the format.
+fn write_state<P>(path: P, table: &FxHashMap<SmolStr, StateEntry>) -> io::Result<()>
 where P: AsRef<Path> {
     let mut file = File::create(path)?;
-    for (key, value) in table {
-        writeln!(file, "{}{}", key, value)?;
+    for (hash, entry) in table {
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            hash, entry.size, entry.mtime_ns, entry.partial_hash, entry.src_path, entry.dst_path
+        )?;
     }
     Ok(())
 }
@@ -79,15 +101,111 @@ where P: AsRef<Path> {
     Ok(())
 }
 
-fn hash_file<P>(path: P, hasher: &mut Sha512) -> io::Result<SmolStr>
+/// Hash of the first `PARTIAL_HASH_SIZE` bytes of a file. Cheap pre-filter:
+/// files with different partial hashes are guaranteed distinct, but a match
+/// must be confirmed with `hash_full` before two files are treated as equal.
+fn hash_partial<P>(path: P) -> io::Result<SmolStr>
 where P: AsRef<Path> {
     let mut file = File::open(path)?;
-    // Only compute the hash of the first x bytes to make it faster.
-    // If hash collisions are detected, tune this.
-    let mut buffer = [0; 1048576];
+    let mut buffer = [0; PARTIAL_HASH_SIZE];
     let n = file.read(&mut buffer)?;
+    let mut hasher = Sha512::new();
     hasher.update(&buffer[..n]);
-    Ok(format!("{:x}", hasher.finalize_reset()).into())
+    Ok(format!("{:x}", hasher.finalize()).into())
+}
+
+/// Hash of the entire contents of a file. Only worth paying for once
+/// `hash_partial` has turned up a collision.
+fn hash_full<P>(path: P) -> io::Result<SmolStr>
+where P: AsRef<Path> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha512::new();
+    let mut buffer = [0; PARTIAL_HASH_SIZE];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()).into())
+}
+
+/// A container/codec identified from a file's content rather than its name.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SniffedKind {
+    Mp3,
+    Flac,
+    Ogg,
+    M4a,
+    Aiff,
+    Mod,
+    Xm,
+}
+
+impl SniffedKind {
+    fn should_convert(self) -> bool {
+        !matches!(self, SniffedKind::Mp3)
+    }
+
+    /// The extension this content would declare if named honestly.
+    fn extension(self) -> &'static str {
+        match self {
+            SniffedKind::Mp3 => "mp3",
+            SniffedKind::Flac => "flac",
+            SniffedKind::Ogg => "ogg",
+            SniffedKind::M4a => "m4a",
+            SniffedKind::Aiff => "aiff",
+            SniffedKind::Mod => "mod",
+            SniffedKind::Xm => "xm",
+        }
+    }
+}
+
+/// Does the file at `path` look like a tracker module? Protracker-family
+/// mod files carry their signature at a fixed offset rather than at the
+/// start of the file.
+fn looks_like_mod<P>(path: P) -> io::Result<bool>
+where P: AsRef<Path> {
+    let mut file = File::open(path)?;
+    if file.seek(io::SeekFrom::Start(1080)).is_err() {
+        return Ok(false);
+    }
+    let mut signature = [0; 4];
+    match file.read_exact(&mut signature) {
+        Ok(()) => Ok(matches!(&signature, b"M.K." | b"M!K!" | b"FLT4" | b"FLT8" | b"4CHN" | b"6CHN" | b"8CHN")),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Identify a file's real container/codec from its leading magic bytes,
+/// independent of its extension. Returns `None` if nothing recognizable was
+/// found.
+fn sniff_kind<P>(path: P) -> io::Result<Option<SniffedKind>>
+where P: AsRef<Path> {
+    let path = path.as_ref();
+    let mut file = File::open(path)?;
+    let mut buffer = [0; 16];
+    let n = file.read(&mut buffer)?;
+    let buffer = &buffer[..n];
+    Ok(if buffer.starts_with(b"ID3") || (buffer.len() >= 2 && buffer[0] == 0xFF && buffer[1] & 0xE0 == 0xE0) {
+        Some(SniffedKind::Mp3)
+    } else if buffer.starts_with(b"fLaC") {
+        Some(SniffedKind::Flac)
+    } else if buffer.starts_with(b"OggS") {
+        Some(SniffedKind::Ogg)
+    } else if buffer.len() >= 8 && &buffer[4..8] == b"ftyp" {
+        Some(SniffedKind::M4a)
+    } else if buffer.starts_with(b"FORM") {
+        Some(SniffedKind::Aiff)
+    } else if buffer.starts_with(b"Extended Module: ") {
+        Some(SniffedKind::Xm)
+    } else if looks_like_mod(path)? {
+        Some(SniffedKind::Mod)
+    } else {
+        None
+    })
 }
 
 fn remove_non_existent_files<P>(dir: P, files: &FxHashSet<SmolStr>) -> io::Result<()>
@@ -111,18 +229,69 @@ where P: AsRef<Path> {
     Ok(())
 }
 
+struct CachedEntry {
+    hash: SmolStr,
+    size: u64,
+    mtime_ns: u128,
+    partial_hash: SmolStr,
+}
+
+/// Reindex the previous run's records by source path, so a source file's
+/// cached hash can be looked up by stat alone, without reading it.
+fn index_by_src_path(prev_state: &FxHashMap<SmolStr, StateEntry>) -> FxHashMap<SmolStr, CachedEntry> {
+    prev_state
+        .iter()
+        .map(|(hash, entry)| {
+            (
+                entry.src_path.clone(),
+                CachedEntry { hash: hash.clone(), size: entry.size, mtime_ns: entry.mtime_ns, partial_hash: entry.partial_hash.clone() },
+            )
+        })
+        .collect()
+}
+
+struct Candidate {
+    path: PathBuf,
+    relative: PathBuf,
+    src_relative: SmolStr,
+    should_convert: bool,
+    size: u64,
+    mtime_ns: u128,
+    partial_hash: SmolStr,
+    cached_hash: Option<SmolStr>,
+}
+
+/// A hashed/copied file, ready to be folded into `new_state`/`files`/
+/// `to_convert` by the single-threaded caller.
+struct SyncResult {
+    hash: SmolStr,
+    size: u64,
+    mtime_ns: u128,
+    partial_hash: SmolStr,
+    src_path: SmolStr,
+    dst_path: SmolStr,
+    to_convert: Option<(PathBuf, PathBuf)>,
+}
+
 fn add_new_files<P>(
     src: P,
     dst: P,
-    prev_state: FxHashMap<SmolStr, SmolStr>,
-    new_state: &mut FxHashMap<SmolStr, SmolStr>,
+    jobs: usize,
+    prev_state: FxHashMap<SmolStr, StateEntry>,
+    new_state: &mut FxHashMap<SmolStr, StateEntry>,
     files: &mut FxHashSet<SmolStr>,
     to_convert: &mut Vec<(PathBuf, PathBuf)>,
 ) -> io::Result<()>
 where
-    P: AsRef<Path>,
+    P: AsRef<Path> + Sync,
 {
-    let mut hasher: Sha512 = Default::default();
+    let prev_by_src = index_by_src_path(&prev_state);
+
+    // Walk src, stat every candidate file and sniff its real content type
+    // from its magic bytes rather than trusting the extension. Cheap
+    // relative to hashing, so this stays sequential; the expensive hashing
+    // work happens below, in parallel.
+    let mut discovered = Vec::new();
     for entry in WalkDir::new(&src) {
         let entry = entry?;
         let metadata = entry.metadata()?;
@@ -130,85 +299,344 @@ where
             continue;
         }
         let path = entry.path();
-        let Some(ext) = path.extension() else { continue };
-        let ext = ext.to_str().unwrap();
-        let should_convert = EXTENSIONS_TO_CONVERT.contains(&ext);
-        if !(should_convert || ext == "mp3") {
+        let ext = path.extension().and_then(|ext| ext.to_str());
+        if let Some(ext) = ext {
+            if !(EXTENSIONS_TO_CONVERT.contains(&ext) || ext == "mp3") {
+                continue;
+            }
+        }
+        let Some(kind) = sniff_kind(path)? else {
+            if ext.is_some() {
+                eprintln!("[{}] {}", "UNRECOGNIZED".bold().cyan(), path.display());
+            }
             continue;
+        };
+        if let Some(ext) = ext {
+            if ext != kind.extension() {
+                eprintln!(
+                    "[{}] {} (declared .{}, detected .{})",
+                    "BAD EXT".bold().magenta(),
+                    path.display(),
+                    ext,
+                    kind.extension()
+                );
+            }
         }
-        let hash = hash_file(path, &mut hasher)?;
+        let should_convert = kind.should_convert();
+        let src_relative: SmolStr = path.strip_prefix(&src).unwrap().to_str().unwrap().into();
         let relative =
             undepthify(path.strip_prefix(&src).unwrap()).with_extension("mp3");
-        let entry_dst = dst.as_ref().join(&relative);
-        if let Some(prev_path) = prev_state.get(&hash) {
-            if prev_path != relative.to_str().unwrap() {
-                eprintln!("[{}] {}", "RENAME".bold().blue(), relative.display());
-                fs::create_dir_all(entry_dst.parent().unwrap())?;
-                fs::rename(dst.as_ref().join(prev_path.as_str()), entry_dst)?;
-            }
-        } else {
-            fs::create_dir_all(entry_dst.parent().unwrap())?;
-            if should_convert {
-                to_convert.push((path.to_owned(), entry_dst));
+        let size = metadata.len();
+        let mtime_ns = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        discovered.push((path.to_owned(), relative, src_relative, should_convert, size, mtime_ns));
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build().map_err(io::Error::other)?;
+
+    // First pass: for files whose (size, mtime) match the cached record,
+    // reuse the cached hash and the cached partial hash, so neither has to
+    // be re-read from disk. Everyone else pays only for a partial hash. The
+    // cached partial hash still has to flow into `partial_counts` below --
+    // an untouched cached file can collide with a freshly-discovered one.
+    let candidates: Vec<Candidate> = pool.install(|| {
+        discovered
+            .into_par_iter()
+            .map(|(path, relative, src_relative, should_convert, size, mtime_ns)| {
+                let cached = prev_by_src.get(&src_relative).filter(|cached| cached.size == size && cached.mtime_ns == mtime_ns);
+                let (partial_hash, cached_hash) = match cached {
+                    Some(cached) => (cached.partial_hash.clone(), Some(cached.hash.clone())),
+                    None => (hash_partial(&path)?, None),
+                };
+                Ok(Candidate { path, relative, src_relative, should_convert, size, mtime_ns, partial_hash, cached_hash })
+            })
+            .collect::<io::Result<Vec<Candidate>>>()
+    })?;
+
+    // Files whose partial hash is shared by more than one candidate need a
+    // full read to tell them apart; everyone else's partial hash stands in
+    // for the full hash (for files smaller than PARTIAL_HASH_SIZE, it is
+    // already the full hash).
+    let mut partial_counts: FxHashMap<SmolStr, u32> = Default::default();
+    for candidate in &candidates {
+        *partial_counts.entry(candidate.partial_hash.clone()).or_insert(0) += 1;
+    }
+
+    // Second pass: resolve the final hash (possibly full-hashing on a
+    // collision), then copy/rename on the spot. The filesystem mutations
+    // here touch distinct files and are safe to run concurrently; only the
+    // shared `new_state`/`files`/`to_convert` bookkeeping is funneled
+    // through a channel back to this single-threaded caller.
+    let (tx, rx) = mpsc::channel();
+    pool.install(|| {
+        candidates.into_par_iter().try_for_each_with(tx, |tx, candidate| -> io::Result<()> {
+            let Candidate { path, relative, src_relative, should_convert, size, mtime_ns, partial_hash, cached_hash } =
+                candidate;
+            let path = path.as_path();
+            // A cache hit is only trustworthy if its partial hash turned out
+            // to be unique this run; otherwise the cached file may actually
+            // collide with a freshly-discovered one and both need a full
+            // read to tell apart, same as any other fresh collision.
+            let hash = match cached_hash {
+                Some(hash) if partial_counts[&partial_hash] <= 1 => hash,
+                _ if partial_counts[&partial_hash] > 1 => hash_full(path)?,
+                _ => partial_hash.clone(),
+            };
+            let entry_dst = dst.as_ref().join(&relative);
+            let mut to_convert = None;
+            // A hit recorded for some other source file that still exists is
+            // a byte-identical duplicate, not a move: it gets its own copy
+            // instead of renaming the other file's destination away.
+            let moved = prev_state.get(&hash).filter(|prev| {
+                prev.src_path == src_relative || !src.as_ref().join(prev.src_path.as_str()).exists()
+            });
+            if let Some(prev) = moved {
+                if prev.dst_path != relative.to_str().unwrap() {
+                    eprintln!("[{}] {}", "RENAME".bold().blue(), relative.display());
+                    fs::create_dir_all(entry_dst.parent().unwrap())?;
+                    fs::rename(dst.as_ref().join(prev.dst_path.as_str()), entry_dst)?;
+                }
             } else {
-                eprintln!("[{}] {}", "COPY".bold().green(), relative.display());
-                fs::copy(path, entry_dst)?;
+                fs::create_dir_all(entry_dst.parent().unwrap())?;
+                if should_convert {
+                    to_convert = Some((path.to_owned(), entry_dst));
+                } else {
+                    eprintln!("[{}] {}", "COPY".bold().green(), relative.display());
+                    fs::copy(path, entry_dst)?;
+                }
             }
+            let dst_path = SmolStr::from(relative.to_str().unwrap());
+            tx.send(SyncResult { hash, size, mtime_ns, partial_hash, src_path: src_relative, dst_path, to_convert }).unwrap();
+            Ok(())
+        })
+    })?;
+
+    // Results arrive in whatever order the pool finishes them in. When two
+    // source files are byte-identical (same full hash), whichever result
+    // lands in `new_state` last wins the canonical row; fold them in a
+    // deterministic order (by source path) so that pick doesn't flip between
+    // runs purely from thread scheduling and cause spurious RENAMEs.
+    let mut results: Vec<SyncResult> = rx.iter().collect();
+    results.sort_by(|a, b| a.src_path.cmp(&b.src_path));
+    for result in results {
+        if new_state.contains_key(&result.hash) {
+            eprintln!("[{}] {}", "HASH COLLISION".bold().red(), result.dst_path);
         }
-        let new_path = SmolStr::from(relative.to_str().unwrap());
-        if new_state.contains_key(&hash) {
-            eprintln!("[{}] {}", "HASH COLLISION".bold().red(), relative.display());
+        if let Some(pair) = result.to_convert {
+            to_convert.push(pair);
         }
-        new_state.insert(hash, new_path.clone());
-        files.insert(new_path);
+        files.insert(result.dst_path.clone());
+        new_state.insert(
+            result.hash,
+            StateEntry {
+                size: result.size,
+                mtime_ns: result.mtime_ns,
+                partial_hash: result.partial_hash,
+                src_path: result.src_path,
+                dst_path: result.dst_path,
+            },
+        );
     }
     Ok(())
 }
 
-fn convert_files(to_convert: &[(PathBuf, PathBuf)]) -> io::Result<()> {
-    let mut jobs: Vec<Child> = Vec::with_capacity(MAX_JOBS);
-    for (src, dst) in to_convert {
-        if jobs.len() >= MAX_JOBS {
-            eprintln!(" --- Waiting for jobs --- ");
-            for job in &mut jobs {
-                job.wait()?;
-            }
-            jobs.clear();
-        }
-        eprintln!("[{}] {}", "CONVERT".bold().yellow(), src.display());
-        jobs.push(
-            Command::new("ffmpeg")
-                .arg("-y")
-                .arg("-i")
-                .arg(src)
-                .arg("-ab")
-                .arg("256k")
-                .arg("-hide_banner")
-                .arg("-loglevel")
-                .arg("error")
-                .arg(dst)
-                .spawn()?,
-        );
+/// Spawn an ffmpeg conversion of `src` into `dst` at the given bitrate.
+fn spawn_ffmpeg(src: &Path, dst: &Path, bitrate: &str) -> io::Result<Child> {
+    eprintln!("[{}] {}", "CONVERT".bold().yellow(), src.display());
+    Command::new("ffmpeg")
+        .arg("-y")
+        .arg("-i")
+        .arg(src)
+        .arg("-ab")
+        .arg(bitrate)
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .arg(dst)
+        .spawn()
+}
+
+/// Run ffmpeg conversions through a sliding window of `jobs` children: as
+/// soon as one finishes, the next pending conversion is spawned into its
+/// slot, so the pool stays saturated instead of stalling on a batch's
+/// slowest member. Returns the destinations whose conversion failed (a
+/// non-zero ffmpeg exit), so the caller can avoid recording them as synced.
+fn convert_files(to_convert: &[(PathBuf, PathBuf)], jobs: usize, bitrate: usize) -> io::Result<Vec<PathBuf>> {
+    // A window of 0 would never spawn anything, silently dropping every
+    // pending conversion while the caller has already recorded them as
+    // synced; always keep at least one slot open.
+    let jobs = jobs.max(1);
+    let bitrate = format!("{bitrate}k");
+    let mut pending = to_convert.iter();
+    let mut running: Vec<(Child, &Path, &Path)> = Vec::with_capacity(jobs);
+    let mut failed = Vec::new();
+
+    for (src, dst) in pending.by_ref().take(jobs) {
+        running.push((spawn_ffmpeg(src, dst, &bitrate)?, src, dst));
     }
-    eprintln!(" --- Waiting for jobs --- ");
-    for job in &mut jobs {
-        job.wait()?;
+
+    while !running.is_empty() {
+        let done = loop {
+            if let Some(i) = running.iter_mut().position(|(child, ..)| matches!(child.try_wait(), Ok(Some(_)))) {
+                break i;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        };
+        let (mut child, src, dst) = running.remove(done);
+        if !child.wait()?.success() {
+            eprintln!("[{}] {}", "CONVERT FAILED".bold().red(), src.display());
+            failed.push(dst.to_owned());
+        }
+        if let Some((src, dst)) = pending.next() {
+            running.push((spawn_ffmpeg(src, dst, &bitrate)?, src, dst));
+        }
     }
-    eprintln!(" --- Done --- ");
-    Ok(())
+    Ok(failed)
 }
 
-pub fn musync<P>(src: P, dst: P) -> io::Result<()>
-where P: AsRef<Path> {
-    let mut new_state: FxHashMap<SmolStr, SmolStr> = Default::default();
+pub fn musync<P>(src: P, dst: P, jobs: usize, bitrate: usize) -> io::Result<()>
+where P: AsRef<Path> + Sync {
+    let mut new_state: FxHashMap<SmolStr, StateEntry> = Default::default();
     let mut files: FxHashSet<SmolStr> = Default::default();
     let mut to_convert: Vec<(PathBuf, PathBuf)> = Default::default();
     let state_file = dst.as_ref().join(STATE_FILE);
-    let prev_state = read_table(&state_file, 128)?;
-    add_new_files(&src, &dst, prev_state, &mut new_state, &mut files, &mut to_convert)?;
-    convert_files(&to_convert)?;
+    let prev_state = read_state(&state_file)?;
+    add_new_files(&src, &dst, jobs, prev_state, &mut new_state, &mut files, &mut to_convert)?;
+    let failed = convert_files(&to_convert, jobs, bitrate)?;
+    for dst_path in &failed {
+        let relative = dst_path.strip_prefix(dst.as_ref()).unwrap().to_str().unwrap();
+        files.remove(relative);
+        new_state.retain(|_, entry| entry.dst_path.as_str() != relative);
+    }
     remove_non_existent_files(&dst, &files)?;
-    write_table(state_file, &new_state)?;
+    write_state(state_file, &new_state)?;
     remove_empty_directories(dst)?;
     Ok(())
 }
+
+/// Is the file at `dst_path` an intact rendering of the source file sniffed
+/// as `kind`? Copied files must still hash to the recorded digest; converted
+/// files can't (lossy transcode), so they're only checked for a non-empty,
+/// recognizable mp3 container.
+fn is_intact(kind: SniffedKind, dst_path: &Path, hash: &SmolStr) -> io::Result<bool> {
+    if fs::metadata(dst_path).map(|metadata| metadata.len()).unwrap_or(0) == 0 {
+        return Ok(false);
+    }
+    if kind.should_convert() {
+        Ok(sniff_kind(dst_path)? == Some(SniffedKind::Mp3))
+    } else {
+        // The recorded hash may be only the partial (first-block) digest --
+        // `add_new_files` only escalates to a full hash when a partial-hash
+        // collision forces it. Check both tiers before declaring the copy
+        // damaged, same as the escalation it was produced with.
+        if hash_partial(dst_path)? == *hash {
+            return Ok(true);
+        }
+        Ok(hash_full(dst_path)? == *hash)
+    }
+}
+
+/// Re-check `dst` against the `.musync` state table: entries whose
+/// destination file is missing, empty, or no longer an intact rendering of
+/// its source are re-derived from `src` (re-copied or re-converted), so a
+/// library damaged by an interrupted run or on-disk corruption can be
+/// scrubbed without a full wipe-and-resync.
+pub fn verify<P>(src: P, dst: P, jobs: usize, bitrate: usize) -> io::Result<()>
+where P: AsRef<Path> + Sync {
+    let state_file = dst.as_ref().join(STATE_FILE);
+    let state = read_state(&state_file)?;
+    let mut to_convert: Vec<(PathBuf, PathBuf)> = Vec::new();
+    let mut repaired = 0usize;
+    let mut unrecoverable = 0usize;
+
+    for (hash, entry) in &state {
+        let src_path = src.as_ref().join(entry.src_path.as_str());
+        let dst_path = dst.as_ref().join(entry.dst_path.as_str());
+
+        let kind = sniff_kind(&src_path).ok().flatten();
+        if let Some(kind) = kind {
+            if is_intact(kind, &dst_path, hash)? {
+                continue;
+            }
+        }
+
+        let Some(kind) = kind else {
+            eprintln!("[{}] {} (source missing or unrecognizable)", "UNRECOVERABLE".bold().red(), entry.dst_path);
+            unrecoverable += 1;
+            continue;
+        };
+
+        eprintln!("[{}] {}", "REPAIR".bold().yellow(), entry.dst_path);
+        fs::create_dir_all(dst_path.parent().unwrap())?;
+        if kind.should_convert() {
+            to_convert.push((src_path, dst_path));
+        } else {
+            fs::copy(&src_path, &dst_path)?;
+        }
+        repaired += 1;
+    }
+
+    let failed = convert_files(&to_convert, jobs, bitrate)?;
+    for dst_path in &failed {
+        eprintln!("[{}] {}", "REPAIR FAILED".bold().red(), dst_path.display());
+    }
+    eprintln!(
+        "Repaired {} file(s), {} unrecoverable (source missing or unrecognizable)",
+        repaired - failed.len(),
+        unrecoverable
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /// Write `contents` to a fresh file in the system temp directory and
+    /// return its path.
+    fn write_fixture(name: &str, contents: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn partial_hash_collision_escalates_to_distinct_full_hashes() {
+        let shared_prefix = vec![0xAB; PARTIAL_HASH_SIZE];
+        let mut a = shared_prefix.clone();
+        a.extend_from_slice(b"tail-a");
+        let mut b = shared_prefix;
+        b.extend_from_slice(b"tail-b");
+
+        let path_a = write_fixture("musync_test_partial_collision_a", &a);
+        let path_b = write_fixture("musync_test_partial_collision_b", &b);
+
+        // Same first PARTIAL_HASH_SIZE bytes, so the cheap pre-filter alone
+        // would wrongly call these identical; only the full hash tells them
+        // apart, which is what `add_new_files` escalates to before ever
+        // treating two candidates as the same state-table row.
+        assert_eq!(hash_partial(&path_a).unwrap(), hash_partial(&path_b).unwrap());
+        assert_ne!(hash_full(&path_a).unwrap(), hash_full(&path_b).unwrap());
+
+        fs::remove_file(path_a).unwrap();
+        fs::remove_file(path_b).unwrap();
+    }
+
+    #[test]
+    fn identical_content_hashes_identically_at_both_tiers() {
+        let contents = vec![0x7C; PARTIAL_HASH_SIZE + 64];
+        let path_a = write_fixture("musync_test_duplicate_a", &contents);
+        let path_b = write_fixture("musync_test_duplicate_b", &contents);
+
+        // Truly duplicate files are indistinguishable by content and collapse
+        // onto the same state-table row; only one source path survives as
+        // canonical, and `add_new_files` copies the other again on each run
+        // rather than renaming the canonical file's destination.
+        assert_eq!(hash_partial(&path_a).unwrap(), hash_partial(&path_b).unwrap());
+        assert_eq!(hash_full(&path_a).unwrap(), hash_full(&path_b).unwrap());
+
+        fs::remove_file(path_a).unwrap();
+        fs::remove_file(path_b).unwrap();
+    }
+}